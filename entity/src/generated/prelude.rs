@@ -1,3 +1,4 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.10
 
+pub use super::centroid::Entity as Centroid;
 pub use super::search::Entity as Search;
@@ -2,17 +2,36 @@
 
 use sea_orm::entity::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "search")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     pub content: String,
-    #[sea_orm(column_type = "custom(\"vector\")")]
-    pub vector: String, // Should be 'PgVector' without attribute macro
+    pub vector: PgVector,
+    pub title: Option<String>,
+    pub weight: Option<f64>,
+    pub centroid_id: Option<i32>,
+    pub expires_at: Option<DateTimeWithTimeZone>,
+    pub indexed_at: DateTimeWithTimeZone,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::centroid::Entity",
+        from = "Column::CentroidId",
+        to = "super::centroid::Column::Id",
+        on_update = "NoAction",
+        on_delete = "SetNull"
+    )]
+    Centroid,
+}
+
+impl Related<super::centroid::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Centroid.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -1,12 +1,67 @@
 pub use sea_orm_migration::prelude::*;
 
+use sea_orm::{ConnectOptions, Database, DbConn, Statement};
+
 mod m20250414_131949_create_search;
+mod m20261015_090000_add_search_title;
+mod m20261015_100000_add_search_weight;
+mod m20261015_110000_create_centroid;
+mod m20261015_120000_add_search_expires_at;
+mod m20261015_130000_add_search_indexed_at;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20250414_131949_create_search::Migration)]
+        vec![
+            Box::new(m20250414_131949_create_search::Migration),
+            Box::new(m20261015_090000_add_search_title::Migration),
+            Box::new(m20261015_100000_add_search_weight::Migration),
+            Box::new(m20261015_110000_create_centroid::Migration),
+            Box::new(m20261015_120000_add_search_expires_at::Migration),
+            Box::new(m20261015_130000_add_search_indexed_at::Migration),
+        ]
+    }
+}
+
+pub fn is_valid_schema_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 63
+        && matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Connects with the migrator's schema on the search path, creating the schema if needed.
+/// `public` stays on the path so the `vector` type still resolves when the extension lives there.
+pub async fn connect_with_schema(connect_options: ConnectOptions) -> Result<DbConn, DbErr> {
+    let db = Database::connect(connect_options.clone()).await?;
+    let search_path: String = db
+        .query_one(Statement::from_string(
+            db.get_database_backend(),
+            "SELECT current_setting('search_path')",
+        ))
+        .await?
+        .ok_or_else(|| DbErr::Custom("Cannot read search_path".to_owned()))?
+        .try_get_by_index(0)?;
+    let schema = search_path.trim_matches('"');
+    if schema == "public" {
+        return Ok(db);
+    }
+    if !is_valid_schema_name(schema) {
+        return Err(DbErr::Custom(format!("Invalid schema name {:?}", schema)));
     }
+    db.execute_unprepared(&format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", schema))
+        .await?;
+    // the baseline migration would otherwise install pgvector into the first tenant's schema, out
+    // of reach of every other tenant's search path
+    db.execute_unprepared("CREATE EXTENSION IF NOT EXISTS vector SCHEMA public")
+        .await?;
+    db.close().await?;
+    Database::connect(
+        ConnectOptions::new(connect_options.get_url())
+            .set_schema_search_path(format!("{},public", schema))
+            .to_owned(),
+    )
+    .await
 }
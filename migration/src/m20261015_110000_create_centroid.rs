@@ -0,0 +1,85 @@
+use sea_orm::TransactionTrait;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let tx = manager.get_connection().begin().await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Centroid::Table)
+                    .col(
+                        ColumnDef::new(Centroid::Id)
+                            .integer()
+                            .not_null()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    .col(ColumnDef::new(Centroid::Vector).vector(None).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Search::Table)
+                    .add_column(ColumnDef::new(Search::CentroidId).integer().null())
+                    .add_foreign_key(
+                        TableForeignKey::new()
+                            .name("fk_search_centroid_id")
+                            .from_tbl(Search::Table)
+                            .from_col(Search::CentroidId)
+                            .to_tbl(Centroid::Table)
+                            .to_col(Centroid::Id)
+                            .on_delete(ForeignKeyAction::SetNull),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let tx = manager.get_connection().begin().await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Search::Table)
+                    .drop_foreign_key(Alias::new("fk_search_centroid_id"))
+                    .drop_column(Search::CentroidId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Centroid::Table).to_owned())
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Centroid {
+    Table,
+    Id,
+    Vector,
+}
+
+#[derive(DeriveIden)]
+enum Search {
+    Table,
+    CentroidId,
+}
@@ -1,24 +1,68 @@
-use anyhow::{anyhow, Result};
-use clap::{Args, Parser, Subcommand};
-use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use migration::IntoCondition;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, FixedOffset, TimeDelta};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use fastembed::{EmbeddingModel, ExecutionProviderDispatch, InitOptions, TextEmbedding};
+use ort::execution_providers::{
+    CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider,
+};
 use sea_orm::sea_query::extension::postgres::PgBinOper;
 use sea_orm::sea_query::ExprTrait;
-use sea_orm::sea_query::{Expr, Order};
-use sea_orm::QueryOrder;
+use sea_orm::sea_query::{Alias, Expr, Func, JoinType, Order, Query, SimpleExpr};
 use sea_orm::{
     prelude::PgVector,
     ActiveValue::{NotSet, Set},
-    ConnectOptions, Database, EntityTrait, PaginatorTrait, QueryFilter, QuerySelect,
+    ColumnTrait, Condition, ConnectOptions, ConnectionTrait, Database, DatabaseConnection,
+    EntityTrait, FromQueryResult, PaginatorTrait, QueryFilter, QuerySelect, Select,
+    TransactionTrait,
 };
+use sea_orm::{QueryOrder, QueryTrait};
 use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncBufReadExt;
 
 #[derive(Args, Debug, Serialize, Clone)]
 pub struct CliArgs {
     #[arg(short = 'd', long, env = "DATABASE_URL")]
-    pub database_url: url::Url,
+    pub database_url: Option<url::Url>,
     #[arg(short = 't', long, default_value = "0.6")]
     pub threashold: Option<f32>,
+    #[arg(long, value_enum)]
+    pub db_sslmode: Option<SslMode>,
+    #[arg(long)]
+    pub db_sslrootcert: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t)]
+    pub device: Device,
+    #[arg(long, env = "DATABASE_SCHEMA")]
+    pub db_schema: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Clone, Copy, Default)]
+pub enum Device {
+    #[default]
+    Cpu,
+    Cuda,
+    Coreml,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Clone, Copy)]
+pub enum SslMode {
+    Disable,
+    Require,
+    VerifyFull,
+}
+
+impl SslMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Require => "require",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
 }
 
 #[derive(Parser, Debug, Serialize)]
@@ -33,68 +77,1327 @@ pub struct Cli {
 #[derive(Args, Debug, Serialize)]
 #[command(author, version, about)]
 pub struct Create {
-    pub content: String,
+    #[arg(required_unless_present = "stream", conflicts_with = "stream")]
+    pub content: Option<String>,
+    #[arg(long)]
+    pub title: Option<String>,
+    #[arg(long)]
+    pub weight: Option<f64>,
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub ttl: Option<Duration>,
+    #[arg(long)]
+    pub stream: bool,
+    #[arg(long, default_value = "32", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=4096))]
+    pub stream_batch_size: usize,
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    pub flush_interval: Duration,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct Count {
+    #[arg(long)]
+    pub indexed_before: Option<DateTime<FixedOffset>>,
 }
 
 #[derive(Args, Debug, Serialize)]
 #[command(author, version, about)]
 pub struct Search {
-    pub query: String,
+    #[arg(required_unless_present = "query_from_file")]
+    pub query: Option<String>,
+    #[arg(long, conflicts_with = "query")]
+    pub query_from_file: Option<PathBuf>,
+    /// Parse `term:weight` tokens in the query
+    #[arg(long, conflicts_with = "query_from_file")]
+    pub weighted_query: bool,
+    #[arg(long)]
+    pub unlike: Option<String>,
+    #[arg(long, default_value = "0.5", value_parser = |value: &str| parse_f32(value, |weight| weight > 0.0, "a positive number"))]
+    pub unlike_weight: f32,
+    #[arg(long, value_parser = |value: &str| parse_f32(value, |floor| (-1.0..=1.0).contains(&floor), "a similarity between -1 and 1"))]
+    pub similarity_floor: Option<f32>,
+    #[arg(long)]
+    pub include_vector: bool,
+    #[arg(long)]
+    pub print_sql: bool,
+    #[arg(long)]
+    pub id_after: Option<i32>,
+    #[arg(long)]
+    pub id_before: Option<i32>,
+    #[arg(long)]
+    pub explain_filter: bool,
+    #[arg(long)]
+    pub use_weight: bool,
+    #[arg(long, default_value = "stdout")]
+    pub sink: Sink,
+    #[arg(long)]
+    pub probes: Option<u64>,
+    #[arg(long)]
+    pub exclude_expired: bool,
+    /// Digits after the decimal point; f32 has no meaningful digits past about 9
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(..=17))]
+    pub float_precision: Option<usize>,
+    #[arg(long)]
+    pub diverse: bool,
+    #[arg(long, default_value = "0.5", value_parser = |value: &str| parse_f32(value, |lambda| (0.0..=1.0).contains(&lambda), "a number between 0 and 1"))]
+    pub mmr_lambda: f32,
+    #[arg(long, default_value = "40")]
+    pub mmr_candidates: u64,
+    #[arg(long, value_enum, default_value_t)]
+    pub content_strategy: ContentStrategy,
+}
+
+#[derive(ValueEnum, Debug, Serialize, Clone, Copy, Default)]
+pub enum ContentStrategy {
+    Full,
+    #[default]
+    Truncate,
+    /// Only ids and scores, for callers that already have the content
+    Omit,
+    /// A preview starting at the first query term
+    Snippet,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum Sink {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(sink: &str) -> Result<Self, Self::Err> {
+        match sink.split_once(':') {
+            None if sink == "stdout" => Ok(Sink::Stdout),
+            Some(("file", path)) if !path.is_empty() => Ok(Sink::File(path.into())),
+            _ => Err(format!("expected stdout or file:<path>, got {:?}", sink)),
+        }
+    }
+}
+
+impl Sink {
+    fn open(&self) -> Result<Box<dyn Write>> {
+        Ok(match self {
+            Sink::Stdout => Box::new(std::io::stdout().lock()),
+            Sink::File(path) => {
+                Box::new(BufWriter::new(File::create(path).with_context(|| {
+                    format!("Cannot create sink file {}", path.display())
+                })?))
+            }
+        })
+    }
+}
+
+#[derive(FromQueryResult, Debug)]
+pub struct SearchHit {
+    pub id: i32,
+    pub score: f64,
+    pub title: Option<String>,
+    pub content: String,
+    pub vector: Option<PgVector>,
+}
+
+fn parse_f32(value: &str, valid: fn(f32) -> bool, expected: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(parsed) if parsed.is_finite() && valid(parsed) => Ok(parsed),
+        _ => Err(format!("expected {}", expected)),
+    }
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct Tokenize {
+    pub content: String,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct Reembed {
+    #[arg(long)]
+    pub resume_after: Option<i32>,
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..))]
+    pub batch_size: u64,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct VerifyDeterminism {
+    #[arg(long, default_value = "0", value_parser = |value: &str| parse_f32(value, |tolerance| tolerance >= 0.0, "a non-negative number"))]
+    pub tolerance: f32,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct Centroids {
+    pub k: usize,
+    #[arg(long, default_value = "10000")]
+    pub sample_size: u64,
+    #[arg(long, default_value = "20")]
+    pub iterations: usize,
+    #[arg(long)]
+    pub assign: bool,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct ModelInfo {
+    pub model: String,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct VerifyVectors {
+    #[arg(long)]
+    pub repair: bool,
+    #[arg(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..))]
+    pub batch_size: u64,
+}
+
+#[derive(Args, Debug, Serialize)]
+#[command(author, version, about)]
+pub struct DistanceHistogram {
+    #[arg(long, default_value = "1000")]
+    pub sample_size: u64,
+    #[arg(long, default_value = "10", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=1000))]
+    pub buckets: usize,
+}
+
+#[derive(Debug)]
+pub struct HistogramBucket {
+    pub from: f64,
+    pub to: f64,
+    pub count: usize,
 }
 
 #[derive(Subcommand, Debug, Serialize)]
 pub enum Commands {
     Create(Create),
-    Count,
+    Count(Count),
     Search(Search),
+    Tokenize(Tokenize),
+    Reembed(Reembed),
+    VerifyDeterminism(VerifyDeterminism),
+    Centroids(Centroids),
+    Expire,
+    ModelInfo(ModelInfo),
+    VerifyVectors(VerifyVectors),
+    DistanceHistogram(DistanceHistogram),
+}
+
+const SEARCH_LIMIT: u64 = 10;
+// Output dimension of AllMiniLML6V2
+const EMBEDDING_DIMENSION: i32 = 384;
+const CONTENT_PREVIEW_CHARS: usize = 200;
+const HISTOGRAM_WIDTH: usize = 40;
+
+const DETERMINISM_SAMPLES: &[&str] = &[
+    "The quick brown fox jumps over the lazy dog.",
+    "Vector search finds documents by meaning rather than keywords.",
+    "PostgreSQL stores embeddings with the pgvector extension.",
+    "",
+    "A much longer passage repeats itself to exercise batching and padding. \
+     A much longer passage repeats itself to exercise batching and padding. \
+     A much longer passage repeats itself to exercise batching and padding.",
+];
+
+// `is_available` only means the provider is compiled into the runtime, so it is registered with
+// `error_on_failure` and a failed session falls back to CPU in `load_embedding_model`
+fn execution_provider<E>(provider: E) -> Result<(&'static str, ExecutionProviderDispatch)>
+where
+    E: ExecutionProvider + Into<ExecutionProviderDispatch>,
+{
+    if provider.supported_by_platform() && provider.is_available()? {
+        Ok((provider.as_str(), provider.into().error_on_failure()))
+    } else {
+        eprintln!("{} is unavailable, falling back to CPU", provider.as_str());
+        execution_provider(CPUExecutionProvider::default())
+    }
+}
+
+fn embedding_model(device: Device) -> Result<TextEmbedding> {
+    load_embedding_model(device, EmbeddingModel::AllMiniLML6V2)
+}
+
+fn load_embedding_model(device: Device, model: EmbeddingModel) -> Result<TextEmbedding> {
+    let (name, execution_provider) = match device {
+        Device::Cpu => execution_provider(CPUExecutionProvider::default())?,
+        Device::Cuda => execution_provider(CUDAExecutionProvider::default())?,
+        Device::Coreml => execution_provider(CoreMLExecutionProvider::default())?,
+    };
+    let cpu = CPUExecutionProvider::default().as_str();
+    let embedding_model = match TextEmbedding::try_new(
+        InitOptions::new(model.clone()).with_execution_providers(vec![execution_provider]),
+    ) {
+        Err(err) if name != cpu => {
+            eprintln!("{} failed to load ({}), falling back to CPU", name, err);
+            return load_embedding_model(Device::Cpu, model);
+        }
+        embedding_model => embedding_model?,
+    };
+    eprintln!("execution provider: {}", name);
+    Ok(embedding_model)
+}
+
+fn create_embeddings<S: AsRef<str> + Send + Sync>(
+    device: Device,
+    contents: Vec<S>,
+) -> Result<Vec<Vec<f32>>> {
+    embedding_model(device)?.embed(contents, None)
 }
 
-fn create_embedding(content: impl AsRef<str> + Send + Sync) -> Result<PgVector> {
+fn create_embedding(device: Device, content: impl AsRef<str> + Send + Sync) -> Result<PgVector> {
     Ok(PgVector::from(
-        TextEmbedding::try_new(InitOptions::new(EmbeddingModel::AllMiniLML6V2))?
-            .embed(vec![content], None)?
+        create_embeddings(device, vec![content])?
             .into_iter()
             .next()
             .ok_or_else(|| anyhow!("No embed"))?,
     ))
 }
 
+fn query_text(search: &Search) -> Result<String> {
+    let Some(query_file) = &search.query_from_file else {
+        return search.query.clone().ok_or_else(|| anyhow!("No query"));
+    };
+    let query = std::fs::read_to_string(query_file)
+        .with_context(|| format!("Cannot read query file {}", query_file.display()))?;
+    if query.trim().is_empty() {
+        return Err(anyhow!("Query file {} is empty", query_file.display()));
+    }
+    Ok(query)
+}
+
+// With --weighted-query, `cats:2.0 dogs:1.0` weighs each term; words without a weight join the
+// next weighted term, and a query without any weights is a single term
+fn weighted_terms(query: &str) -> Vec<(String, f32)> {
+    let mut terms = Vec::new();
+    let mut words = Vec::new();
+    for token in query.split_whitespace() {
+        let weighted = token
+            .rsplit_once(':')
+            .and_then(|(word, weight)| Some((word, weight.parse::<f32>().ok()?)))
+            .filter(|(_, weight)| weight.is_finite());
+        match weighted {
+            Some((word, weight)) => {
+                words.push(word);
+                terms.push((words.join(" ").trim().to_owned(), weight));
+                words.clear();
+            }
+            None => words.push(token),
+        }
+    }
+    if terms.is_empty() {
+        return vec![(query.to_owned(), 1.0)];
+    }
+    terms.push((words.join(" "), 1.0));
+    terms.retain(|(term, _)| !term.is_empty());
+    terms
+}
+
+fn query_terms(search: &Search) -> Result<Vec<(String, f32)>> {
+    let query = query_text(search)?;
+    Ok(if search.weighted_query {
+        weighted_terms(&query)
+    } else {
+        vec![(query, 1.0)]
+    })
+}
+
+fn query_embedding(device: Device, search: &Search, terms: &[(String, f32)]) -> Result<PgVector> {
+    let mut terms = terms.to_vec();
+    if let Some(unlike) = &search.unlike {
+        terms.push((unlike.clone(), -search.unlike_weight));
+    }
+    let (texts, weights): (Vec<_>, Vec<_>) = terms.into_iter().unzip();
+    Ok(PgVector::from(combine_embeddings(
+        create_embeddings(device, texts)?,
+        &weights,
+    )?))
+}
+
+fn combine_embeddings(embeddings: Vec<Vec<f32>>, weights: &[f32]) -> Result<Vec<f32>> {
+    let mut embeddings = embeddings.into_iter().zip(weights);
+    let (mut combined, weight) = embeddings.next().ok_or_else(|| anyhow!("No embed"))?;
+    combined.iter_mut().for_each(|x| *x *= weight);
+    for (embedding, weight) in embeddings {
+        combined
+            .iter_mut()
+            .zip(embedding)
+            .for_each(|(x, y)| *x += weight * y);
+    }
+    // a zero vector has no cosine distance to anything, so every row would be filtered out
+    if combined.iter().all(|x| *x == 0.0) || combined.iter().any(|x| !x.is_finite()) {
+        return Err(anyhow!("Query term weights cancel out to a zero vector"));
+    }
+    Ok(combined)
+}
+
+fn threshold_filter(expr: &SimpleExpr, threshold: Option<f32>, search: &Search) -> Condition {
+    Condition::all()
+        .add(expr.clone().lt(threshold))
+        // cosine similarity is `1 - distance`, so `similarity >= floor` is `distance <= 1 - floor`
+        .add_option(
+            search
+                .similarity_floor
+                .map(|floor| expr.clone().lte(1.0 - floor)),
+        )
+}
+
+fn search_filter(search: &Search, vector: &PgVector) -> Condition {
+    Condition::all()
+        .add_option(search.id_after.map(|id| entity::search::Column::Id.gt(id)))
+        .add_option(search.id_before.map(|id| entity::search::Column::Id.lt(id)))
+        .add_option(search.exclude_expired.then(|| {
+            Condition::any()
+                .add(entity::search::Column::ExpiresAt.is_null())
+                .add(Expr::col(entity::search::Column::ExpiresAt).gt(Expr::current_timestamp()))
+        }))
+        // probing trades recall for speed: neighbours assigned to other centroids, or to none, are missed
+        .add_option(search.probes.map(|probes| {
+            entity::search::Column::CentroidId
+                .in_subquery(nearest_centroids(vector.clone(), probes).into_query())
+        }))
+}
+
+fn nearest_centroids(vector: PgVector, limit: u64) -> Select<entity::centroid::Entity> {
+    entity::centroid::Entity::find()
+        .select_only()
+        .column(entity::centroid::Column::Id)
+        .order_by(
+            Expr::col(entity::centroid::Column::Vector).binary(PgBinOper::CosineDistance, vector),
+            Order::Asc,
+        )
+        .limit(limit)
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn nearest_mean(means: &[Vec<f32>], sample: &[f32]) -> usize {
+    means
+        .iter()
+        .map(|mean| mean.iter().zip(sample).map(|(a, b)| a * b).sum::<f32>())
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap_or_default()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm =
+        a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        dot / norm
+    } else {
+        0.0
+    }
+}
+
+fn hit_vector(hit: &SearchHit) -> &[f32] {
+    hit.vector.as_ref().map_or(&[], |vector| vector.as_slice())
+}
+
+// Maximal Marginal Relevance: greedily pick the candidate maximizing
+// `lambda * score - (1 - lambda) * max sim(hit, selected)`, where the score is the search score
+// (weighted with --use-weight)
+fn select_diverse(candidates: Vec<SearchHit>, limit: usize, lambda: f32) -> Vec<SearchHit> {
+    let mut remaining = candidates
+        .into_iter()
+        .map(|hit| (hit.score as f32, hit))
+        .collect::<Vec<_>>();
+    let mut selected = Vec::<SearchHit>::with_capacity(limit);
+    while selected.len() < limit && !remaining.is_empty() {
+        let best = remaining
+            .iter()
+            .map(|(relevance, hit)| {
+                let redundancy = selected
+                    .iter()
+                    .map(|chosen| cosine_similarity(hit_vector(hit), hit_vector(chosen)))
+                    .reduce(f32::max)
+                    .unwrap_or_default();
+                lambda * relevance - (1.0 - lambda) * redundancy
+            })
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        selected.push(remaining.remove(best).1);
+    }
+    selected
+}
+
+// Spherical k-means: samples and means are unit vectors, so the largest dot product is the
+// smallest cosine distance. The samples arrive in random order, so the first `k` seed the means.
+fn spherical_kmeans(samples: &[Vec<f32>], k: usize, iterations: usize) -> Vec<Vec<f32>> {
+    let mut means = samples[..k].to_vec();
+    let mut assignments = vec![usize::MAX; samples.len()];
+    for _ in 0..iterations {
+        let mut sums = vec![Vec::<f32>::new(); k];
+        let mut changed = false;
+        for (sample, assignment) in samples.iter().zip(&mut assignments) {
+            let nearest = nearest_mean(&means, sample);
+            changed |= *assignment != nearest;
+            *assignment = nearest;
+            let sum = &mut sums[nearest];
+            sum.resize(sample.len().max(sum.len()), 0.0);
+            sum.iter_mut().zip(sample).for_each(|(sum, x)| *sum += x);
+        }
+        if !changed {
+            break;
+        }
+        for (mean, sum) in means.iter_mut().zip(sums) {
+            if !sum.is_empty() {
+                *mean = normalize(sum);
+            }
+        }
+    }
+    means
+}
+
+async fn compute_centroids(db_conn: &DatabaseConnection, centroids: Centroids) -> Result<()> {
+    let samples = entity::search::Entity::find()
+        .select_only()
+        .column(entity::search::Column::Vector)
+        .order_by(Expr::cust("random()"), Order::Asc)
+        .limit(centroids.sample_size)
+        .into_tuple::<PgVector>()
+        .all(db_conn)
+        .await?
+        .into_iter()
+        .map(|vector| normalize(vector.to_vec()))
+        .collect::<Vec<_>>();
+    if centroids.k == 0 || samples.len() < centroids.k {
+        return Err(anyhow!(
+            "Cannot compute {} centroids from {} sampled documents",
+            centroids.k,
+            samples.len()
+        ));
+    }
+    let means = spherical_kmeans(&samples, centroids.k, centroids.iterations);
+
+    let tx = db_conn.begin().await?;
+    // replacing the centroids clears every document's centroid_id through the foreign key
+    entity::centroid::Entity::delete_many().exec(&tx).await?;
+    entity::centroid::Entity::insert_many(means.into_iter().map(|mean| {
+        entity::centroid::ActiveModel {
+            id: NotSet,
+            vector: Set(PgVector::from(mean)),
+        }
+    }))
+    .exec(&tx)
+    .await?;
+    println!("centroids: {}", centroids.k);
+    if centroids.assign {
+        let nearest = Query::select()
+            .column(entity::centroid::Column::Id)
+            .from(entity::centroid::Entity)
+            .order_by_expr(
+                Expr::col((entity::centroid::Entity, entity::centroid::Column::Vector)).binary(
+                    PgBinOper::CosineDistance,
+                    Expr::col((entity::search::Entity, entity::search::Column::Vector)),
+                ),
+                Order::Asc,
+            )
+            .limit(1)
+            .to_owned();
+        let assigned = entity::search::Entity::update_many()
+            .col_expr(
+                entity::search::Column::CentroidId,
+                SimpleExpr::SubQuery(None, Box::new(nearest.into_sub_query_statement())),
+            )
+            .exec(&tx)
+            .await?
+            .rows_affected;
+        println!("assigned: {}", assigned);
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+fn print_tokens(device: Device, content: &str) -> Result<()> {
+    let mut tokenizer = embedding_model(device)?.tokenizer;
+    let max_length = tokenizer
+        .get_truncation()
+        .map(|truncation| truncation.max_length);
+    let encoding = tokenizer
+        .with_truncation(None)
+        .map_err(|err| anyhow!(err))?
+        .encode(content, true)
+        .map_err(|err| anyhow!(err))?;
+    println!("tokens: {:?}", encoding.get_tokens());
+    println!("count: {}", encoding.len());
+    if let Some(max_length) = max_length.filter(|max_length| encoding.len() > *max_length) {
+        println!("truncated to: {}", max_length);
+    }
+    Ok(())
+}
+
+fn format_vector(vector: &[f32], float_precision: Option<usize>) -> String {
+    match float_precision {
+        Some(precision) => format!(
+            "[{}]",
+            vector
+                .iter()
+                .map(|x| format!("{:.*}", precision, x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => format!("{:?}", vector),
+    }
+}
+
+fn preview(content: &str, start: usize) -> String {
+    let mut chars = content[start..].chars();
+    let mut preview = chars
+        .by_ref()
+        .take(CONTENT_PREVIEW_CHARS)
+        .collect::<String>();
+    if start > 0 {
+        preview.insert(0, '…');
+    }
+    if chars.next().is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+// Words of the positively weighted query terms, longest first: long words are the most
+// distinctive, and stopwords are short
+fn snippet_words(terms: &[(String, f32)]) -> Vec<String> {
+    let mut words = terms
+        .iter()
+        .filter(|(_, weight)| *weight > 0.0)
+        .flat_map(|(term, _)| term.split_whitespace())
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>();
+    words.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    words.dedup();
+    words
+}
+
+// Byte offset of the word containing the first match of the earliest listed word that occurs in
+// the content. Both sides are ASCII-lowercased, which keeps byte offsets, so a match in the folded
+// content is at the same offset in the original.
+fn snippet_start(content: &str, words: &[String]) -> usize {
+    let folded = content.to_ascii_lowercase();
+    words
+        .iter()
+        .find_map(|word| folded.find(word.as_str()))
+        .map(|index| {
+            content[..index]
+                .rfind(char::is_whitespace)
+                .map_or(0, |space| {
+                    space + content[space..].chars().next().map_or(0, char::len_utf8)
+                })
+        })
+        .unwrap_or_default()
+}
+
+fn write_hit(out: &mut dyn Write, hit: SearchHit, search: &Search, words: &[String]) -> Result<()> {
+    let mut line = match search.content_strategy {
+        ContentStrategy::Omit => format!("{} {:.4}", hit.id, hit.score),
+        ContentStrategy::Full => format!("{:?}", hit.content),
+        ContentStrategy::Truncate => format!("{:?}", preview(&hit.content, 0)),
+        ContentStrategy::Snippet => format!(
+            "{:?}",
+            preview(&hit.content, snippet_start(&hit.content, words))
+        ),
+    };
+    if let Some(title) = hit
+        .title
+        .filter(|_| !matches!(search.content_strategy, ContentStrategy::Omit))
+    {
+        line = format!("{}: {}", title, line);
+    }
+    if let Some(vector) = hit.vector {
+        line = format!(
+            "{} {}",
+            line,
+            format_vector(vector.as_slice(), search.float_precision)
+        );
+    }
+    writeln!(out, "{}", line)?;
+    Ok(())
+}
+
+async fn insert_batch(
+    db_conn: &DatabaseConnection,
+    model: &TextEmbedding,
+    create: &Create,
+    contents: Vec<String>,
+) -> Result<Vec<i32>> {
+    // the TTL runs from ingestion, not from when the stream started
+    let expires_at = expires_at(db_conn, create.ttl).await?;
+    let embeddings = model.embed(contents.clone(), None)?;
+    let tx = db_conn.begin().await?;
+    let mut documents = Vec::with_capacity(contents.len());
+    for (content, embedding) in contents.into_iter().zip(embeddings) {
+        let vector = PgVector::from(embedding);
+        let centroid_id = nearest_centroids(vector.clone(), 1)
+            .into_tuple::<i32>()
+            .one(&tx)
+            .await?;
+        documents.push(entity::search::ActiveModel {
+            id: NotSet,
+            vector: Set(vector),
+            content: Set(content),
+            title: Set(create.title.clone()),
+            weight: Set(create.weight),
+            centroid_id: Set(centroid_id),
+            expires_at: Set(expires_at),
+            indexed_at: NotSet,
+        });
+    }
+    let ids = entity::search::Entity::insert_many(documents)
+        .exec_with_returning_keys(&tx)
+        .await?;
+    tx.commit().await?;
+    Ok(ids)
+}
+
+// Each non-empty stdin line is a document. A batch is ingested once it is full, `flush_interval`
+// after its first line arrived, and on EOF, so a slow producer never waits on a partial batch.
+async fn stream_documents(
+    db_conn: &DatabaseConnection,
+    device: Device,
+    create: &Create,
+) -> Result<()> {
+    let model = embedding_model(device)?;
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut batch = Vec::with_capacity(create.stream_batch_size);
+    let mut deadline = None;
+    let mut ingested = 0;
+    loop {
+        let next_line = lines.next_line();
+        // `None` means the flush deadline passed before the next line arrived
+        let line = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, next_line).await.ok(),
+            None => Some(next_line.await),
+        };
+        let eof = match line {
+            Some(line) => match line.context("Cannot read stdin")? {
+                Some(line) => {
+                    if !line.trim().is_empty() {
+                        if batch.is_empty() {
+                            deadline = Some(tokio::time::Instant::now() + create.flush_interval);
+                        }
+                        batch.push(line);
+                    }
+                    if batch.len() < create.stream_batch_size {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            None => false,
+        };
+        if !batch.is_empty() {
+            ingested += batch.len();
+            for id in insert_batch(db_conn, &model, create, std::mem::take(&mut batch)).await? {
+                println!("id: {}", id);
+            }
+            eprintln!("ingested: {}", ingested);
+        }
+        deadline = None;
+        if eof {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn store_embeddings(
+    db_conn: &DatabaseConnection,
+    ids: Vec<i32>,
+    embeddings: Vec<Vec<f32>>,
+) -> Result<()> {
+    let tx = db_conn.begin().await?;
+    for (id, embedding) in ids.into_iter().zip(embeddings) {
+        let vector = PgVector::from(embedding);
+        // the old assignment was made in the old vector space
+        let centroid_id = nearest_centroids(vector.clone(), 1)
+            .into_tuple::<i32>()
+            .one(&tx)
+            .await?;
+        entity::search::Entity::update_many()
+            .col_expr(entity::search::Column::Vector, Expr::value(vector))
+            .col_expr(entity::search::Column::CentroidId, Expr::value(centroid_id))
+            .col_expr(
+                entity::search::Column::IndexedAt,
+                Expr::current_timestamp().into(),
+            )
+            .filter(entity::search::Column::Id.eq(id))
+            .exec(&tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn verify_vectors(
+    db_conn: &DatabaseConnection,
+    device: Device,
+    verify: VerifyVectors,
+) -> Result<()> {
+    let dims = Func::cust(Alias::new("vector_dims")).arg(Expr::col(entity::search::Column::Vector));
+    let model = if verify.repair {
+        Some(embedding_model(device)?)
+    } else {
+        None
+    };
+    let mut last_id = i32::MIN;
+    let (mut bad, mut repaired) = (0, 0);
+    loop {
+        let documents = entity::search::Entity::find()
+            .select_only()
+            .column(entity::search::Column::Id)
+            .column(entity::search::Column::Content)
+            .expr(dims.clone())
+            .filter(entity::search::Column::Id.gt(last_id))
+            .filter(Expr::expr(dims.clone()).ne(EMBEDDING_DIMENSION))
+            .order_by_asc(entity::search::Column::Id)
+            .limit(verify.batch_size)
+            .into_tuple::<(i32, String, i32)>()
+            .all(db_conn)
+            .await?;
+        let Some((id, _, _)) = documents.last() else {
+            break;
+        };
+        last_id = *id;
+        bad += documents.len();
+        let mut ids = Vec::with_capacity(documents.len());
+        let mut contents = Vec::with_capacity(documents.len());
+        for (id, content, dimension) in documents {
+            eprintln!("id {}: {} dimensions", id, dimension);
+            ids.push(id);
+            contents.push(content);
+        }
+        if let Some(model) = &model {
+            let embeddings = model.embed(contents, None)?;
+            if let Some(embedding) = embeddings
+                .iter()
+                .find(|embedding| embedding.len() != EMBEDDING_DIMENSION as usize)
+            {
+                return Err(anyhow!(
+                    "Model produced {} dimensions, expected {}",
+                    embedding.len(),
+                    EMBEDDING_DIMENSION
+                ));
+            }
+            repaired += ids.len();
+            store_embeddings(db_conn, ids, embeddings).await?;
+        }
+    }
+    println!("bad: {}", bad);
+    if verify.repair {
+        println!("repaired: {}", repaired);
+    }
+    Ok(())
+}
+
+fn histogram(distances: &[f64], buckets: usize) -> Vec<HistogramBucket> {
+    let min = distances.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = distances.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / buckets as f64;
+    let mut histogram = (0..buckets)
+        .map(|bucket| HistogramBucket {
+            from: min + width * bucket as f64,
+            to: min + width * (bucket + 1) as f64,
+            count: 0,
+        })
+        .collect::<Vec<_>>();
+    for distance in distances {
+        // the maximum falls in the last bucket; equal distances give a zero width (NaN, cast to 0)
+        let bucket = ((distance - min) / width) as usize;
+        histogram[bucket.min(buckets - 1)].count += 1;
+    }
+    histogram
+}
+
+// Self-kNN over a random sample: each sampled document's cosine distance to its nearest other
+// document
+async fn print_distance_histogram(
+    db_conn: &DatabaseConnection,
+    distance_histogram: DistanceHistogram,
+) -> Result<()> {
+    let (sample, candidate, nearest, distance) = (
+        Alias::new("sample"),
+        Alias::new("candidate"),
+        Alias::new("nearest"),
+        Alias::new("distance"),
+    );
+    let samples = Query::select()
+        .columns([entity::search::Column::Id, entity::search::Column::Vector])
+        .from(entity::search::Entity)
+        .order_by_expr(Expr::cust("random()"), Order::Asc)
+        .limit(distance_histogram.sample_size)
+        .to_owned();
+    let expr = Expr::col((candidate.clone(), entity::search::Column::Vector)).binary(
+        PgBinOper::CosineDistance,
+        Expr::col((sample.clone(), entity::search::Column::Vector)),
+    );
+    let nearest_neighbor = Query::select()
+        .expr_as(expr.clone(), distance.clone())
+        .from_as(entity::search::Entity, candidate.clone())
+        .and_where(
+            Expr::col((candidate, entity::search::Column::Id))
+                .ne(Expr::col((sample.clone(), entity::search::Column::Id))),
+        )
+        .order_by_expr(expr, Order::Asc)
+        .limit(1)
+        .to_owned();
+    // one query: every sample joins its nearest other document through LATERAL
+    let query = Query::select()
+        .column((nearest.clone(), distance))
+        .from_subquery(samples, sample)
+        .join_lateral(
+            JoinType::InnerJoin,
+            nearest_neighbor,
+            nearest,
+            Condition::all(),
+        )
+        .to_owned();
+    let distances = db_conn
+        .query_all(db_conn.get_database_backend().build(&query))
+        .await?
+        .into_iter()
+        .map(|row| row.try_get::<f64>("", "distance"))
+        .collect::<Result<Vec<_>, _>>()?;
+    if distances.is_empty() {
+        return Err(anyhow!(
+            "Need at least two documents for nearest-neighbor distances"
+        ));
+    }
+    let histogram = histogram(&distances, distance_histogram.buckets);
+    let largest = histogram
+        .iter()
+        .map(|bucket| bucket.count)
+        .max()
+        .unwrap_or_default();
+    for bucket in &histogram {
+        println!(
+            "{:.4}..{:.4} {:>6} {}",
+            bucket.from,
+            bucket.to,
+            bucket.count,
+            "#".repeat(bucket.count * HISTOGRAM_WIDTH / largest)
+        );
+    }
+    println!("samples: {}", distances.len());
+    Ok(())
+}
+
+async fn reembed_documents(
+    db_conn: &DatabaseConnection,
+    device: Device,
+    reembed: Reembed,
+) -> Result<()> {
+    let model = embedding_model(device)?;
+    let mut last_id = reembed.resume_after.unwrap_or(i32::MIN);
+    let mut reembedded = 0;
+    loop {
+        let documents = entity::search::Entity::find()
+            .select_only()
+            .column(entity::search::Column::Id)
+            .column(entity::search::Column::Content)
+            .filter(entity::search::Column::Id.gt(last_id))
+            .order_by_asc(entity::search::Column::Id)
+            .limit(reembed.batch_size)
+            .into_tuple::<(i32, String)>()
+            .all(db_conn)
+            .await?;
+        let Some((id, _)) = documents.last() else {
+            break;
+        };
+        last_id = *id;
+        let (ids, contents): (Vec<_>, Vec<_>) = documents.into_iter().unzip();
+        reembedded += ids.len();
+        store_embeddings(db_conn, ids, model.embed(contents, None)?).await?;
+        eprintln!("reembedded: {} (last id: {})", reembedded, last_id);
+    }
+    println!("reembedded: {}", reembedded);
+    Ok(())
+}
+
+fn verify_determinism(device: Device, verify: &VerifyDeterminism) -> Result<()> {
+    let first = create_embeddings(device, DETERMINISM_SAMPLES.to_vec())?;
+    let second = create_embeddings(device, DETERMINISM_SAMPLES.to_vec())?;
+    let mut drifted = 0;
+    for ((sample, first), second) in DETERMINISM_SAMPLES.iter().zip(first).zip(second) {
+        let drift = first
+            .iter()
+            .zip(&second)
+            .map(|(first, second)| (first - second).abs())
+            .fold(0.0, f32::max);
+        if drift > verify.tolerance {
+            drifted += 1;
+            println!("drift {:e}: {:?}", drift, sample);
+        }
+    }
+    if drifted > 0 {
+        return Err(anyhow!(
+            "{} of {} embeddings drifted",
+            drifted,
+            DETERMINISM_SAMPLES.len()
+        ));
+    }
+    println!("deterministic: {} embeddings", DETERMINISM_SAMPLES.len());
+    Ok(())
+}
+
+fn resident_memory_kb() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn print_model_info(device: Device, model_info: &ModelInfo) -> Result<()> {
+    let info = TextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| {
+            info.model_code.eq_ignore_ascii_case(&model_info.model)
+                || format!("{:?}", info.model).eq_ignore_ascii_case(&model_info.model)
+        })
+        .ok_or_else(|| anyhow!("Unknown model {:?}", model_info.model))?;
+    let memory_before = resident_memory_kb();
+    let started = Instant::now();
+    let model = load_embedding_model(device, info.model)?;
+    let load_time = started.elapsed();
+    let memory_after = resident_memory_kb();
+    println!("model: {}", info.model_code);
+    println!("dimension: {}", info.dim);
+    if let Some(truncation) = model.tokenizer.get_truncation() {
+        println!("max length: {}", truncation.max_length);
+    }
+    println!("load time: {:.3}s", load_time.as_secs_f64());
+    match memory_before.zip(memory_after) {
+        Some((before, after)) => println!("memory: +{} kB", after.saturating_sub(before)),
+        None => println!("memory: unknown"),
+    }
+    Ok(())
+}
+
+fn database_url(cliargs: &CliArgs) -> Result<url::Url> {
+    let mut database_url = cliargs
+        .database_url
+        .clone()
+        .ok_or_else(|| anyhow!("No database URL, pass --database-url or set DATABASE_URL"))?;
+    if let Some(sslmode) = cliargs.db_sslmode {
+        database_url
+            .query_pairs_mut()
+            .append_pair("sslmode", sslmode.as_str());
+    }
+    if let Some(sslrootcert) = &cliargs.db_sslrootcert {
+        std::fs::File::open(sslrootcert)
+            .with_context(|| format!("Cannot load root certificate {}", sslrootcert.display()))?;
+        database_url
+            .query_pairs_mut()
+            .append_pair("sslrootcert", &sslrootcert.to_string_lossy());
+    }
+    Ok(database_url)
+}
+
+// The expiry is based on the database clock, which Expire and --exclude-expired compare against
+async fn expires_at(
+    db_conn: &impl ConnectionTrait,
+    ttl: Option<Duration>,
+) -> Result<Option<DateTime<FixedOffset>>> {
+    let Some(ttl) = ttl else {
+        return Ok(None);
+    };
+    let now = db_conn
+        .query_one(
+            db_conn
+                .get_database_backend()
+                .build(Query::select().expr(Expr::current_timestamp())),
+        )
+        .await?
+        .ok_or_else(|| anyhow!("Cannot read current_timestamp"))?
+        .try_get_by_index::<DateTime<FixedOffset>>(0)?;
+    TimeDelta::from_std(ttl)
+        .ok()
+        .and_then(|ttl| now.checked_add_signed(ttl))
+        .map(Some)
+        .ok_or_else(|| anyhow!("TTL {} is out of range", humantime::format_duration(ttl)))
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> Result<()> {
     let args = Cli::parse();
 
+    match &args.commands {
+        Commands::Tokenize(tokenize) => {
+            return print_tokens(args.cliargs.device, &tokenize.content)
+        }
+        Commands::VerifyDeterminism(verify) => {
+            return verify_determinism(args.cliargs.device, verify)
+        }
+        Commands::ModelInfo(model_info) => {
+            return print_model_info(args.cliargs.device, model_info)
+        }
+        _ => {}
+    }
+
     let db_conn = {
-        let connect_opts = ConnectOptions::from(args.cliargs.database_url);
+        let mut connect_opts = ConnectOptions::from(database_url(&args.cliargs)?);
+        if let Some(schema) = &args.cliargs.db_schema {
+            if !migration::is_valid_schema_name(schema) {
+                return Err(anyhow!("Invalid schema name {:?}", schema));
+            }
+            connect_opts.set_schema_search_path(format!("{},public", schema));
+        }
         Database::connect(connect_opts).await?
     };
 
     match args.commands {
         Commands::Create(create) => {
-            entity::search::Entity::insert(entity::search::ActiveModel {
+            if create
+                .weight
+                .is_some_and(|weight| !weight.is_finite() || weight < 0.0)
+            {
+                return Err(anyhow!("Weight must be finite and not negative"));
+            }
+            let expires_at = expires_at(&db_conn, create.ttl).await?;
+            if create.stream {
+                stream_documents(&db_conn, args.cliargs.device, &create).await?;
+                return Ok(());
+            }
+            let content = create.content.ok_or_else(|| anyhow!("No content"))?;
+            let vector = create_embedding(args.cliargs.device, &content)?;
+            let centroid_id = nearest_centroids(vector.clone(), 1)
+                .into_tuple::<i32>()
+                .one(&db_conn)
+                .await?;
+            let inserted = entity::search::Entity::insert(entity::search::ActiveModel {
                 id: NotSet,
-                vector: Set(create_embedding(&create.content)?),
-                content: Set(create.content),
+                vector: Set(vector),
+                content: Set(content),
+                title: Set(create.title),
+                weight: Set(create.weight),
+                centroid_id: Set(centroid_id),
+                expires_at: Set(expires_at),
+                indexed_at: NotSet,
             })
             .exec(&db_conn)
             .await?;
+            println!("id: {}", inserted.last_insert_id);
         }
-        Commands::Count => {
-            let search_count = entity::search::Entity::find().count(&db_conn).await?;
+        Commands::Count(count) => {
+            let search_count = entity::search::Entity::find()
+                .apply_if(count.indexed_before, |query, indexed_before| {
+                    query.filter(entity::search::Column::IndexedAt.lt(indexed_before))
+                })
+                .count(&db_conn)
+                .await?;
             println!("rows: {}", search_count);
         }
         Commands::Search(search) => {
+            let mut out = search.sink.open()?;
+            let terms = query_terms(&search)?;
+            let vector = query_embedding(args.cliargs.device, &search, &terms)?;
             let expr = Expr::col(entity::search::Column::Vector)
-                .binary(PgBinOper::CosineDistance, create_embedding(&search.query)?);
-            entity::search::Entity::find()
-                .filter(expr.clone().lt(args.cliargs.threashold))
-                .order_by(expr, Order::Asc)
-                .limit(10)
-                .all(&db_conn)
+                .binary(PgBinOper::CosineDistance, vector.clone());
+            // score = (1 - cosine distance), times coalesce(weight, 1) with --use-weight
+            let mut score = Expr::val(1.0).sub(expr.clone());
+            if search.use_weight {
+                score = score.mul(Func::coalesce([
+                    Expr::col(entity::search::Column::Weight).into(),
+                    Expr::val(1.0).into(),
+                ]));
+            }
+            let mut query = entity::search::Entity::find()
+                .select_only()
+                .column(entity::search::Column::Id)
+                .expr_as(score.clone(), "score")
+                .column(entity::search::Column::Title)
+                .column(entity::search::Column::Content);
+            if search.include_vector || search.diverse {
+                query = query.column(entity::search::Column::Vector);
+            }
+            let limit = if search.diverse {
+                search.mmr_candidates.max(SEARCH_LIMIT)
+            } else {
+                SEARCH_LIMIT
+            };
+            let query = query
+                .filter(threshold_filter(&expr, args.cliargs.threashold, &search))
+                .filter(search_filter(&search, &vector));
+            let query = if search.use_weight {
+                query.order_by(score, Order::Desc)
+            } else {
+                query.order_by(expr.clone(), Order::Asc)
+            }
+            .limit(limit);
+            if search.explain_filter {
+                let matching = entity::search::Entity::find()
+                    .filter(search_filter(&search, &vector))
+                    .count(&db_conn)
+                    .await?;
+                eprintln!("filter matches: {}", matching);
+            }
+            if search.print_sql {
+                eprintln!("{}", query.build(db_conn.get_database_backend()));
+            }
+            let mut hits = query.into_model::<SearchHit>().all(&db_conn).await?;
+            if hits.is_empty() {
+                if entity::search::Entity::find().count(&db_conn).await? == 0 {
+                    eprintln!("no documents to search");
+                } else {
+                    eprintln!("no documents matched");
+                }
+            }
+            if (hits.len() as u64) < SEARCH_LIMIT {
+                let (dropped, closest) = entity::search::Entity::find()
+                    .select_only()
+                    .expr(Func::count(Expr::col(entity::search::Column::Id)))
+                    .expr(Func::min(expr.clone()))
+                    .filter(search_filter(&search, &vector))
+                    .filter(threshold_filter(&expr, args.cliargs.threashold, &search).not())
+                    .into_tuple::<(i64, Option<f64>)>()
+                    .one(&db_conn)
+                    .await?
+                    .unwrap_or_default();
+                if let Some(closest) = closest {
+                    eprintln!(
+                        "threshold dropped {} of {} candidates, best excluded similarity: {:.4}",
+                        dropped,
+                        hits.len() as i64 + dropped,
+                        1.0 - closest
+                    );
+                }
+            }
+            if search.diverse {
+                hits = select_diverse(hits, SEARCH_LIMIT as usize, search.mmr_lambda);
+                if !search.include_vector {
+                    hits.iter_mut().for_each(|hit| hit.vector = None);
+                }
+            }
+            let words = snippet_words(&terms);
+            for hit in hits {
+                write_hit(&mut out, hit, &search, &words)?;
+            }
+            out.flush()?;
+        }
+        Commands::Reembed(reembed) => {
+            reembed_documents(&db_conn, args.cliargs.device, reembed).await?
+        }
+        Commands::Centroids(centroids) => compute_centroids(&db_conn, centroids).await?,
+        Commands::Expire => {
+            let expired = entity::search::Entity::delete_many()
+                .filter(Expr::col(entity::search::Column::ExpiresAt).lte(Expr::current_timestamp()))
+                .exec(&db_conn)
                 .await?
-                .into_iter()
-                .for_each(|search| println!("{:?}", search.content))
+                .rows_affected;
+            println!("expired: {}", expired);
+        }
+        Commands::VerifyVectors(verify) => {
+            verify_vectors(&db_conn, args.cliargs.device, verify).await?
+        }
+        Commands::DistanceHistogram(distance_histogram) => {
+            print_distance_histogram(&db_conn, distance_histogram).await?
+        }
+        Commands::Tokenize(_) | Commands::VerifyDeterminism(_) | Commands::ModelInfo(_) => {
+            unreachable!("runs without a database connection")
         }
     }
     db_conn.close().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(query: &[f32], vector: Vec<f32>) -> SearchHit {
+        SearchHit {
+            id: 0,
+            score: cosine_similarity(query, &vector) as f64,
+            title: None,
+            content: String::new(),
+            vector: Some(PgVector::from(vector)),
+        }
+    }
+
+    #[test]
+    fn diverse_selection_skips_near_duplicates() {
+        let query = [1.0, 1.0];
+        let candidates = || {
+            vec![
+                hit(&query, vec![1.0, 0.8]),
+                hit(&query, vec![1.0, 0.78]),
+                hit(&query, vec![0.7, 1.0]),
+            ]
+        };
+        let redundancy =
+            |hits: &[SearchHit]| cosine_similarity(hit_vector(&hits[0]), hit_vector(&hits[1]));
+        // lambda 1 is plain top-k by relevance
+        let top_k = select_diverse(candidates(), 2, 1.0);
+        let diverse = select_diverse(candidates(), 2, 0.5);
+        assert_eq!(hit_vector(&top_k[1]), [1.0, 0.78]);
+        assert_eq!(hit_vector(&diverse[1]), [0.7, 1.0]);
+        assert!(redundancy(&diverse) < redundancy(&top_k));
+    }
+
+    #[test]
+    fn snippet_starts_at_the_longest_matching_word() {
+        let words = snippet_words(&[("Émile wrote".to_owned(), 1.0)]);
+        assert_eq!(snippet_start("He WROTE about Émile Zola", &words), 15);
+        assert_eq!(snippet_start("The author WROTE novels", &words), 11);
+        assert_eq!(snippet_start("Nothing here", &words), 0);
+    }
+
+    #[test]
+    fn weighted_terms_group_words_with_the_next_weight() {
+        assert_eq!(
+            weighted_terms("big cats:2 dogs:0.5 birds"),
+            vec![
+                ("big cats".to_owned(), 2.0),
+                ("dogs".to_owned(), 0.5),
+                ("birds".to_owned(), 1.0)
+            ]
+        );
+        assert_eq!(
+            weighted_terms("plain query"),
+            vec![("plain query".to_owned(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn term_weights_shift_the_nearest_document() {
+        let (cats, dogs) = (vec![1.0, 0.0], vec![0.0, 1.0]);
+        let nearest = |weights: &[f32]| {
+            let query = combine_embeddings(vec![cats.clone(), dogs.clone()], weights).unwrap();
+            cosine_similarity(&query, &cats) > cosine_similarity(&query, &dogs)
+        };
+        assert!(nearest(&[2.0, 1.0]));
+        assert!(!nearest(&[1.0, 2.0]));
+    }
+
+    #[test]
+    fn unlike_moves_the_query_away_from_the_unliked_concept() {
+        let (query, unliked) = (vec![1.0, 1.0], vec![0.0, 1.0]);
+        let plain = combine_embeddings(vec![query.clone()], &[1.0]).unwrap();
+        let unlike = combine_embeddings(vec![query, unliked.clone()], &[1.0, -0.5]).unwrap();
+        assert!(cosine_similarity(&unlike, &unliked) < cosine_similarity(&plain, &unliked));
+    }
+
+    #[test]
+    fn cancelling_weights_are_rejected() {
+        assert!(combine_embeddings(vec![vec![1.0, 0.0], vec![1.0, 0.0]], &[1.0, -1.0]).is_err());
+    }
+}